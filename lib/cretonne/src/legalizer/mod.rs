@@ -13,12 +13,22 @@
 //! The legalizer does not deal with register allocation constraints. These constraints are derived
 //! from the encoding recipes, and solved later by the register allocator.
 
+use std::fmt;
+
 use cursor::{Cursor, FuncCursor};
+use entity_ref::EntityRef;
 use flowgraph::ControlFlowGraph;
+use ir::condcodes::FloatCC;
+use ir::immediates::{Ieee32, Ieee64};
 use ir::{self, InstBuilder};
 use isa::TargetIsa;
 use bitset::BitSet;
 
+/// Upper bound on how many times a single instruction may be re-expanded before we conclude the
+/// legalization patterns are unsound and bail out. A correct narrow/expand pattern always makes
+/// progress, so a handful of expansions of the *same* instruction is plenty of slack.
+const MAX_INST_EXPANSIONS: u32 = 10;
+
 mod boundary;
 mod globalvar;
 mod heap;
@@ -27,71 +37,505 @@ mod split;
 use self::globalvar::expand_global_addr;
 use self::heap::expand_heap_addr;
 
+/// A dynamic legality predicate: given the data flow graph and an instruction, decide whether that
+/// instruction should be treated as legal regardless of what the ISA encoding tables say.
+///
+/// Returning `true` forces the instruction through untouched (no expansion is attempted, and it is
+/// not reported as illegal even if it has no encoding); returning `false` forces it to be expanded
+/// even if it would otherwise encode directly. This mirrors MLIR's dynamically-legal ops. Call and
+/// return ABI conversion and branch argument simplification still run before the predicate is
+/// consulted.
+pub type DynamicLegalityPredicate<'a> = &'a dyn Fn(&ir::DataFlowGraph, ir::Inst) -> bool;
+
+/// Options controlling how [`legalize_function_partial`] runs the legalizer.
+#[derive(Default)]
+pub struct LegalizeOptions<'a> {
+    /// When set, a predicate consulted before `isa.encode` for every instruction. See
+    /// [`DynamicLegalityPredicate`] for the meaning of its result.
+    pub dynamically_legal: Option<DynamicLegalityPredicate<'a>>,
+
+    /// Run a *partial* legalization. In partial mode, instructions that neither encode nor have an
+    /// expansion pattern are collected and returned instead of being left with an invalid encoding
+    /// for a later pass to trip over. When `false`, such instructions are left untouched, matching
+    /// the historical behavior of [`legalize_function`].
+    pub partial: bool,
+
+    /// Rewrite the NaN results of floating-point instructions that can create a NaN to a single
+    /// canonical quiet NaN before assigning encodings. Instructions that only pass an incoming NaN
+    /// through (such as `fmin`, `fmax` or `fpromote`) are not rewritten. Off by default; embedders that need deterministic float semantics
+    /// across hosts (e.g. sandboxed Wasm execution) opt in here.
+    pub nan_canonicalization: bool,
+}
+
+/// One instruction that the legalizer could not make legal, together with the context needed to
+/// report it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IllegalInst {
+    /// The instruction that is still illegal.
+    pub inst: ir::Inst,
+    /// Its opcode.
+    pub opcode: ir::Opcode,
+    /// The EBB it lives in.
+    pub ebb: ir::Ebb,
+}
+
+/// The set of instructions left illegal by a partial legalization run.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LegalizationErrors {
+    /// The unconverted instructions, in the order they were encountered.
+    pub illegal: Vec<IllegalInst>,
+}
+
+impl fmt::Display for LegalizationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} instruction(s) could not be legalized:", self.illegal.len())?;
+        for i in &self.illegal {
+            writeln!(f, "  {} in {}: {}", i.inst, i.ebb, i.opcode)?;
+        }
+        Ok(())
+    }
+}
+
+impl ::std::error::Error for LegalizationErrors {
+    fn description(&self) -> &str {
+        "function could not be fully legalized"
+    }
+}
+
 /// Legalize `func` for `isa`.
 ///
 /// - Transform any instructions that don't have a legal representation in `isa`.
 /// - Fill out `func.encodings`.
 ///
+/// Any instruction that neither encodes nor has an expansion pattern is left untouched; dereferencing
+/// its `func.encodings` entry later will panic. Use [`legalize_function_partial`] with
+/// `partial: true` to collect those instructions instead.
 pub fn legalize_function(func: &mut ir::Function, cfg: &mut ControlFlowGraph, isa: &TargetIsa) {
+    run_legalizer(func, cfg, isa, &LegalizeOptions::default());
+}
+
+/// Legalize `func` for `isa` with explicit [`LegalizeOptions`].
+///
+/// Runs the legalizer to a fixed point like [`legalize_function`], but honors the dynamic legality
+/// predicate in `opts` and, when `opts.partial` is set, returns the instructions that remain illegal
+/// rather than leaving them with an invalid encoding. Returns `Ok(())` when every instruction was
+/// made legal.
+pub fn legalize_function_partial(
+    func: &mut ir::Function,
+    cfg: &mut ControlFlowGraph,
+    isa: &TargetIsa,
+    opts: &LegalizeOptions,
+) -> Result<(), LegalizationErrors> {
+    let illegal = run_legalizer(func, cfg, isa, opts);
+    if illegal.is_empty() {
+        Ok(())
+    } else {
+        Err(LegalizationErrors { illegal })
+    }
+}
+
+/// Shared legalization driver. Returns the instructions that neither encoded nor expanded; in
+/// non-partial mode this is always empty (such instructions are left in place, as before).
+fn run_legalizer(
+    func: &mut ir::Function,
+    cfg: &mut ControlFlowGraph,
+    isa: &TargetIsa,
+    opts: &LegalizeOptions,
+) -> Vec<IllegalInst> {
     debug_assert!(cfg.is_valid());
 
     boundary::legalize_signatures(func, isa);
 
     func.encodings.resize(func.dfg.num_insts());
 
-    let mut pos = FuncCursor::new(func);
+    let mut illegal = Vec::new();
 
-    // Process EBBs in layout order. Some legalization actions may split the current EBB or append
-    // new ones to the end. We need to make sure we visit those new EBBs too.
-    while let Some(_ebb) = pos.next_ebb() {
-        // Keep track of the cursor position before the instruction being processed, so we can
-        // double back when replacing instructions.
-        let mut prev_pos = pos.position();
+    // Drive legalization with an explicit worklist instead of walking the layout and doubling back
+    // over expanded sequences. Every instruction that still needs to be considered is pushed onto
+    // `worklist`; `queued[inst]` keeps the queue deduplicated so a single instruction is never
+    // pending twice. When an expansion creates new instructions (and possibly new EBBs) we enqueue
+    // only those freshly created instructions rather than rescanning the whole EBB.
+    let mut worklist: Vec<ir::Inst> = Vec::with_capacity(func.dfg.num_insts());
+    let mut queued = vec![false; func.dfg.num_insts()];
 
-        while let Some(inst) = pos.next_inst() {
-            let opcode = pos.func.dfg[inst].opcode();
-
-            // Check for ABI boundaries that need to be converted to the legalized signature.
-            if opcode.is_call() && boundary::handle_call_abi(inst, pos.func, cfg) {
-                // Go back and legalize the inserted argument conversion instructions.
-                pos.set_position(prev_pos);
-                continue;
+    // Seed the queue with every instruction. We push in reverse layout order so that popping from
+    // the back of `worklist` (cheap `Vec::pop`) visits instructions in forward layout order, and
+    // instructions created by an expansion are processed immediately after the instruction that
+    // spawned them -- mirroring the old "double back over the expanded sequence" behavior.
+    {
+        let mut insts = Vec::with_capacity(func.dfg.num_insts());
+        let mut pos = FuncCursor::new(func);
+        while let Some(_ebb) = pos.next_ebb() {
+            while let Some(inst) = pos.next_inst() {
+                insts.push(inst);
             }
+        }
+        for &inst in insts.iter().rev() {
+            worklist.push(inst);
+            queued[inst.index()] = true;
+        }
+    }
 
-            if opcode.is_return() && boundary::handle_return_abi(inst, pos.func, cfg) {
-                // Go back and legalize the inserted return value conversion instructions.
-                pos.set_position(prev_pos);
+    // Per-instruction expansion counter. A sound pattern replaces an instruction with a strictly
+    // simpler sequence, so the same instruction should only be expanded a small, bounded number of
+    // times. Exceeding `MAX_INST_EXPANSIONS` means a pattern is cycling and we abort deterministically
+    // instead of hanging.
+    let mut expansions = vec![0u32; func.dfg.num_insts()];
+
+    // Tracks which instructions have already had their NaN result canonicalized, so the
+    // inserted compare/select -- which is re-queued and revisited for encoding -- doesn't trigger
+    // another round of canonicalization on the same producer. Only consulted when the caller opted
+    // into NaN canonicalization.
+    let mut canonicalized = vec![false; func.dfg.num_insts()];
+    let nan_canon = opts.nan_canonicalization;
+
+    while let Some(inst) = worklist.pop() {
+        queued[inst.index()] = false;
+
+        // Instructions can be removed from the layout by an expansion that replaced them with a
+        // branch elsewhere. Skip anything that is no longer attached.
+        if func.layout.inst_ebb(inst).is_none() {
+            continue;
+        }
+
+        let opcode = func.dfg[inst].opcode();
+
+        // Check for ABI boundaries that need to be converted to the legalized signature. The
+        // inserted argument/return conversion instructions are new, so they are enqueued below.
+        if opcode.is_call() && boundary::handle_call_abi(inst, func, cfg) {
+            // Revisit the call itself (now with legalized arguments) plus the inserted conversions.
+            enqueue(inst, &mut worklist, &mut queued);
+            enqueue_new_insts(func, &mut worklist, &mut queued, &mut expansions, &mut canonicalized);
+            continue;
+        }
+
+        if opcode.is_return() && boundary::handle_return_abi(inst, func, cfg) {
+            enqueue(inst, &mut worklist, &mut queued);
+            enqueue_new_insts(func, &mut worklist, &mut queued, &mut expansions, &mut canonicalized);
+            continue;
+        }
+
+        if opcode.is_branch() {
+            split::simplify_branch_arguments(&mut func.dfg, inst);
+            // Argument simplification can materialize values with new instructions; pick them up.
+            enqueue_new_insts(func, &mut worklist, &mut queued, &mut expansions, &mut canonicalized);
+        }
+
+        // Consult the dynamic legality predicate before the combiner, NaN canonicalization or the
+        // encoding tables touch the instruction.
+        let forced = opts.dynamically_legal.map(|p| p(&func.dfg, inst));
+
+        // Cancel split/concat round-trips before spending an encoding or expansion on them. If the
+        // artifact is combined away, the instructions feeding it may themselves become redundant
+        // artifacts, so the defining instructions of the surviving halves are re-queued.
+        if forced != Some(true) && combine_artifact(inst, func, &mut worklist, &mut queued) {
+            continue;
+        }
+
+        // Canonicalize NaN results before assigning an encoding, so the inserted compare/select
+        // are legalized by the same fixed-point loop. A float producer is rewritten at most once:
+        // the `canonicalized` flag guards against revisiting it after the new instructions are
+        // queued. This matches the `nan_canonicalization` pass later Cranelift runs, and is needed
+        // for deterministic float semantics across sandboxed (Wasm) hosts.
+        if nan_canon && forced != Some(true) && !canonicalized[inst.index()]
+            && produces_nan(opcode)
+            && func.dfg.ctrl_typevar(inst).lane_type().is_float()
+        {
+            canonicalized[inst.index()] = true;
+            canonicalize_nan(inst, func);
+            enqueue(inst, &mut worklist, &mut queued);
+            enqueue_new_insts(func, &mut worklist, &mut queued, &mut expansions, &mut canonicalized);
+            continue;
+        }
+
+        let ctrl_typevar = func.dfg.ctrl_typevar(inst);
+
+        // `changed` is `true` when the instruction was transformed (and must be revisited), `false`
+        // when it is stuck -- neither encodable nor expandable.
+        let changed = match forced {
+            // Declared legal: assign an encoding if one exists, otherwise leave it untouched.
+            Some(true) => {
+                if let Ok(encoding) = isa.encode(&func.dfg, &func.dfg[inst], ctrl_typevar) {
+                    func.encodings[inst] = encoding;
+                }
                 continue;
             }
+            // Declared illegal: skip the encoding tables and expand directly.
+            Some(false) => force_expand(inst, func, cfg),
+            // Normal path: encode if possible, otherwise run the ISA-selected expansion.
+            None => match isa.encode(&func.dfg, &func.dfg[inst], ctrl_typevar) {
+                Ok(encoding) => {
+                    func.encodings[inst] = encoding;
+                    continue;
+                }
+                Err(action) => action(inst, func, cfg),
+            },
+        };
 
-            if opcode.is_branch() {
-                split::simplify_branch_arguments(&mut pos.func.dfg, inst);
+        if changed {
+            // The instruction was transformed into legal equivalents. Only a real transformation
+            // counts against the expansion counter -- it is the point at which an unsound pattern
+            // would cycle, so we abort deterministically if this particular instruction keeps
+            // coming back. A sound pattern re-expands any given instruction only a bounded number
+            // of times; the count scales with instructions actually created rather than a fixed
+            // budget derived from the starting count, so a legitimately deep legalization (e.g. a
+            // wide vector op narrowed to many lanes, each gaining NaN-canon scaffolding) never
+            // trips it.
+            expansions[inst.index()] += 1;
+            if expansions[inst.index()] > MAX_INST_EXPANSIONS {
+                panic!(
+                    "legalizer failed to converge: instruction {} ({}) re-expanded more \
+                     than {} times, the legalization pattern for this opcode is unsound",
+                    inst,
+                    opcode,
+                    MAX_INST_EXPANSIONS
+                );
             }
 
-            match isa.encode(
-                &pos.func.dfg,
-                &pos.func.dfg[inst],
-                pos.func.dfg.ctrl_typevar(inst),
-            ) {
-                Ok(encoding) => pos.func.encodings[inst] = encoding,
-                Err(action) => {
-                    // We should transform the instruction into legal equivalents.
-                    let changed = action(inst, pos.func, cfg);
-                    // If the current instruction was replaced, we need to double back and revisit
-                    // the expanded sequence. This is both to assign encodings and possible to
-                    // expand further.
-                    // There's a risk of infinite looping here if the legalization patterns are
-                    // unsound. Should we attempt to detect that?
-                    if changed {
-                        pos.set_position(prev_pos);
-                        continue;
-                    }
+            // `inst` may have been replaced in place; revisit it so it gets an encoding or a
+            // further expansion. Any brand new instructions (including those in freshly created
+            // EBBs, e.g. from `expand_cond_trap`) are enqueued too.
+            enqueue(inst, &mut worklist, &mut queued);
+            enqueue_new_insts(func, &mut worklist, &mut queued, &mut expansions, &mut canonicalized);
+        } else if opts.partial {
+            // Nothing could legalize this instruction. In partial mode we collect it for the
+            // caller rather than leaving an invalid encoding behind for a later pass to trip over.
+            let ebb = func.layout.pp_ebb(inst);
+            illegal.push(IllegalInst { inst, opcode, ebb });
+        }
+    }
+
+    illegal
+}
+
+/// Expand `inst` using the generated legalization patterns, bypassing the ISA encoding tables.
+///
+/// Used to honor a dynamic legality predicate that forces an otherwise-encodable instruction to be
+/// expanded. Returns whether a pattern matched and transformed the instruction.
+fn force_expand(inst: ir::Inst, func: &mut ir::Function, cfg: &mut ControlFlowGraph) -> bool {
+    expand(inst, func, cfg) || narrow(inst, func, cfg)
+}
+
+/// Does `opcode` compute a floating-point result that may be a *newly created* NaN we want to
+/// canonicalize?
+///
+/// The FP arithmetic opcodes and `sqrt` can synthesize a NaN from non-NaN operands (`0 * inf`,
+/// `sqrt(-1)`); `fdemote` is rewritten as well. Opcodes such as
+/// `fmin`/`fmax`/`ceil`/`floor`/`trunc`/`nearest`/`fpromote` merely propagate an incoming NaN -- which is already canonical if its producer was canonicalized
+/// -- so canonicalizing their outputs would be dead work. Bit-preserving moves, constants, lane
+/// shuffles and integer-to-float conversions never yield a NaN and are likewise excluded.
+fn produces_nan(opcode: ir::Opcode) -> bool {
+    use ir::Opcode::*;
+    match opcode {
+        Fadd | Fsub | Fmul | Fdiv | Fma | Sqrt | Fdemote => true,
+        _ => false,
+    }
+}
+
+/// Rewrite the NaN result of `inst` to a single canonical quiet NaN.
+///
+/// Inserts, right after `inst`, a self-compare that is true exactly when the result is a NaN and a
+/// `select` (or `bitselect` for vectors) that replaces such a result with a canonical quiet-NaN
+/// constant. Downstream uses are redirected to the selected value by reusing the original result
+/// number for the `select`: `DataFlowGraph::replace_result` rebinds `inst`'s result slot to a fresh
+/// value (`raw`) and returns it, leaving the original `result` value free to be redefined by the
+/// `select`. Because existing uses reference `result` by value number, redefining it with the
+/// selected output rewires every one of them without walking the use list. This relies on
+/// `replace_result` touching only the producer's own result binding, which is why it is applied to
+/// the single-result NaN producers selected by [`produces_nan`].
+fn canonicalize_nan(inst: ir::Inst, func: &mut ir::Function) {
+    debug_assert_eq!(
+        func.dfg.inst_results(inst).len(),
+        1,
+        "NaN canonicalization expects a single-result producer"
+    );
+    let result = func.dfg.first_result(inst);
+    let ty = func.dfg.value_type(result);
+    let raw = func.dfg.replace_result(result, ty);
+
+    let mut pos = FuncCursor::new(func).at_inst(inst);
+    pos.next_inst();
+
+    let is_nan = pos.ins().fcmp(FloatCC::Unordered, raw, raw);
+    if ty.is_vector() {
+        let canon = canonical_nan(&mut pos, ty.lane_type());
+        let splat = pos.ins().splat(ty, canon);
+        pos.ins().with_result(result).bitselect(is_nan, splat, raw);
+    } else {
+        let canon = canonical_nan(&mut pos, ty);
+        pos.ins().with_result(result).select(is_nan, canon, raw);
+    }
+}
+
+/// Materialize the canonical quiet NaN constant for scalar float type `ty`.
+fn canonical_nan(pos: &mut FuncCursor, ty: ir::Type) -> ir::Value {
+    match ty {
+        ir::types::F32 => pos.ins().f32const(Ieee32::with_bits(0x7fc0_0000)),
+        ir::types::F64 => pos.ins().f64const(Ieee64::with_bits(0x7ff8_0000_0000_0000)),
+        _ => panic!("cannot canonicalize NaN for non-float type {}", ty),
+    }
+}
+
+/// Push `inst` onto `worklist` unless it is already pending.
+fn enqueue(inst: ir::Inst, worklist: &mut Vec<ir::Inst>, queued: &mut Vec<bool>) {
+    let idx = inst.index();
+    if idx >= queued.len() {
+        queued.resize(idx + 1, false);
+    }
+    if !queued[idx] {
+        queued[idx] = true;
+        worklist.push(inst);
+    }
+}
+
+/// Enqueue every instruction that was created since the bookkeeping vectors were last sized to the
+/// DFG. Expansions allocate contiguous instruction numbers, so any index at or beyond the previous
+/// instruction count belongs to a newly created instruction that still needs to be legalized.
+fn enqueue_new_insts(
+    func: &mut ir::Function,
+    worklist: &mut Vec<ir::Inst>,
+    queued: &mut Vec<bool>,
+    expansions: &mut Vec<u32>,
+    canonicalized: &mut Vec<bool>,
+) {
+    let num_insts = func.dfg.num_insts();
+    func.encodings.resize(num_insts);
+    let old = expansions.len();
+    if num_insts <= old {
+        return;
+    }
+    expansions.resize(num_insts, 0);
+    canonicalized.resize(num_insts, false);
+    for idx in old..num_insts {
+        // Enqueue unconditionally: expansions always attach their instructions to the layout
+        // before returning, but even if one is momentarily detached we still queue it so the
+        // watermark advancing past `idx` can't silently drop it. Detached instructions are skipped
+        // when popped.
+        enqueue(ir::Inst::new(idx), worklist, queued);
+    }
+}
+
+/// Combine away a split/concat legalization artifact around `inst`.
+///
+/// The generated `narrow()` patterns break wide values apart with `isplit`/`vsplit` and reassemble
+/// them with `iconcat`/`vconcat`. When a value is split right after it was concatenated (or a
+/// concat just glues the two halves of a single split back together) the pair is dead weight that
+/// would otherwise have to be register allocated. Modeled on GlobalISel's
+/// `LegalizationArtifactCombiner`, this rewrites the uses of the redundant artifact directly to the
+/// original halves (or source value) and removes it.
+///
+/// Returns `true` if `inst` was combined away, in which case the caller must not encode it. The
+/// instructions producing the surviving values are re-queued so freshly exposed artifacts are
+/// reconsidered. Encodings of any instruction left in place are untouched.
+fn combine_artifact(
+    inst: ir::Inst,
+    func: &mut ir::Function,
+    worklist: &mut Vec<ir::Inst>,
+    queued: &mut Vec<bool>,
+) -> bool {
+    match func.dfg[inst].opcode() {
+        // Fold `split(concat(lo, hi))` -> `(lo, hi)`.
+        op @ ir::Opcode::Isplit | op @ ir::Opcode::Vsplit => {
+            let arg = match func.dfg[inst] {
+                ir::InstructionData::Unary { arg, .. } => func.dfg.resolve_aliases(arg),
+                _ => return false,
+            };
+            let concat = match func.dfg.value_def(arg) {
+                ir::ValueDef::Result(def, _) => def,
+                _ => return false,
+            };
+            let want = if op == ir::Opcode::Isplit {
+                ir::Opcode::Iconcat
+            } else {
+                ir::Opcode::Vconcat
+            };
+            let (lo, hi) = match func.dfg[concat] {
+                ir::InstructionData::Binary { opcode, args } if opcode == want => {
+                    (args[0], args[1])
                 }
+                _ => return false,
+            };
+
+            // Rewrite the split results onto the concat's original halves and drop the split.
+            let (rlo, rhi) = {
+                let results = func.dfg.inst_results(inst);
+                if results.len() != 2 {
+                    return false;
+                }
+                (results[0], results[1])
+            };
+            // `change_to_alias` requires its destination be unattached, and `remove_inst` only
+            // unlinks from the layout -- it leaves the DFG results bound to the dead instruction.
+            // Detach them first, then alias the now-free values onto the concat's halves.
+            func.dfg.clear_results(inst);
+            func.dfg.change_to_alias(rlo, lo);
+            func.dfg.change_to_alias(rhi, hi);
+            func.layout.remove_inst(inst);
+
+            // The concat may now be dead, and the halves might feed further artifacts.
+            requeue_value_def(func, lo, worklist, queued);
+            requeue_value_def(func, hi, worklist, queued);
+            enqueue(concat, worklist, queued);
+            true
+        }
+        // Fold `concat(split(x).lo, split(x).hi)` -> `x`.
+        op @ ir::Opcode::Iconcat | op @ ir::Opcode::Vconcat => {
+            let (lo, hi) = match func.dfg[inst] {
+                ir::InstructionData::Binary { args, .. } => (
+                    func.dfg.resolve_aliases(args[0]),
+                    func.dfg.resolve_aliases(args[1]),
+                ),
+                _ => return false,
+            };
+            let want = if op == ir::Opcode::Iconcat {
+                ir::Opcode::Isplit
+            } else {
+                ir::Opcode::Vsplit
+            };
+            // `lo` and `hi` must be results 0 and 1 of the *same* split instruction.
+            let (lo_def, lo_num) = match func.dfg.value_def(lo) {
+                ir::ValueDef::Result(def, num) => (def, num),
+                _ => return false,
+            };
+            let (hi_def, hi_num) = match func.dfg.value_def(hi) {
+                ir::ValueDef::Result(def, num) => (def, num),
+                _ => return false,
+            };
+            if lo_def != hi_def || lo_num != 0 || hi_num != 1
+                || func.dfg[lo_def].opcode() != want
+            {
+                return false;
             }
+            let src = match func.dfg[lo_def] {
+                ir::InstructionData::Unary { arg, .. } => func.dfg.resolve_aliases(arg),
+                _ => return false,
+            };
 
-            // Remember this position in case we need to double back.
-            prev_pos = pos.position();
+            let result = func.dfg.first_result(inst);
+            // Detach the result from the dead instruction before aliasing it (see the `isplit`
+            // arm): `change_to_alias` asserts its destination is unattached.
+            func.dfg.clear_results(inst);
+            func.dfg.change_to_alias(result, src);
+            func.layout.remove_inst(inst);
+
+            // The split is now potentially dead, and `src`'s producer may fold further.
+            requeue_value_def(func, src, worklist, queued);
+            enqueue(lo_def, worklist, queued);
+            true
         }
+        _ => false,
+    }
+}
+
+/// Re-queue the instruction defining `value`, if any, so it is reconsidered by the combiner.
+fn requeue_value_def(
+    func: &ir::Function,
+    value: ir::Value,
+    worklist: &mut Vec<ir::Inst>,
+    queued: &mut Vec<bool>,
+) {
+    if let ir::ValueDef::Result(def, _) = func.dfg.value_def(value) {
+        enqueue(def, worklist, queued);
     }
 }
 
@@ -102,7 +546,12 @@ pub fn legalize_function(func: &mut ir::Function, cfg: &mut ControlFlowGraph, is
 include!(concat!(env!("OUT_DIR"), "/legalizer.rs"));
 
 /// Custom expansion for conditional trap instructions.
-/// TODO: Add CFG support to the Python patterns so we won't have to do this.
+///
+/// TODO: Express this as a generated `XForm` instead of a hand-written function. Doing so requires
+/// teaching the pattern meta-language (`meta/cretonne/`, not part of this crate's Rust sources) to
+/// declare new EBBs and branch edges and emit the `make_ebb`/`cfg.recompute_ebb` bookkeeping, so
+/// that CFG-splitting legalizations (conditional traps, `br_table`, select-to-branch) need no
+/// bespoke Rust. Until that generator support lands, cond traps are expanded here.
 fn expand_cond_trap(inst: ir::Inst, func: &mut ir::Function, cfg: &mut ControlFlowGraph) {
     // Parse the instruction.
     let trapz;
@@ -146,3 +595,100 @@ fn expand_cond_trap(inst: ir::Inst, func: &mut ir::Function, cfg: &mut ControlFl
     cfg.recompute_ebb(pos.func, old_ebb);
     cfg.recompute_ebb(pos.func, new_ebb);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ir::types::{I32, I64};
+    use ir::{Function, ValueDef};
+
+    /// Return the instruction that defines `value`.
+    fn def_of(func: &ir::Function, value: ir::Value) -> ir::Inst {
+        match func.dfg.value_def(value) {
+            ValueDef::Result(inst, _) => inst,
+            _ => panic!("expected an instruction result"),
+        }
+    }
+
+    #[test]
+    fn fold_split_of_concat() {
+        // `v = iconcat(a, b); (lo, hi) = isplit(v)` should fold so that `lo`/`hi` alias `a`/`b`.
+        let mut func = Function::new();
+        let ebb = func.dfg.make_ebb();
+        func.layout.append_ebb(ebb);
+
+        let (split, a, b, lo, hi) = {
+            let mut pos = FuncCursor::new(&mut func).at_bottom(ebb);
+            let a = pos.ins().iconst(I32, 1);
+            let b = pos.ins().iconst(I32, 2);
+            let v = pos.ins().iconcat(a, b);
+            let (lo, hi) = pos.ins().isplit(v);
+            (def_of(pos.func, lo), a, b, lo, hi)
+        };
+
+        let combined = combine_artifact(split, &mut func, &mut Vec::new(), &mut Vec::new());
+        assert!(combined);
+        assert!(func.layout.inst_ebb(split).is_none());
+        assert_eq!(func.dfg.resolve_aliases(lo), a);
+        assert_eq!(func.dfg.resolve_aliases(hi), b);
+    }
+
+    #[test]
+    fn fold_concat_of_split() {
+        // `(lo, hi) = isplit(v); w = iconcat(lo, hi)` should fold so that `w` aliases `v`.
+        let mut func = Function::new();
+        let ebb = func.dfg.make_ebb();
+        func.layout.append_ebb(ebb);
+
+        let (concat, v, w) = {
+            let mut pos = FuncCursor::new(&mut func).at_bottom(ebb);
+            let v = pos.ins().iconst(I64, 3);
+            let (lo, hi) = pos.ins().isplit(v);
+            let w = pos.ins().iconcat(lo, hi);
+            (def_of(pos.func, w), v, w)
+        };
+
+        let combined = combine_artifact(concat, &mut func, &mut Vec::new(), &mut Vec::new());
+        assert!(combined);
+        assert!(func.layout.inst_ebb(concat).is_none());
+        assert_eq!(func.dfg.resolve_aliases(w), v);
+    }
+
+    #[test]
+    fn produces_nan_only_for_creators() {
+        use ir::Opcode::*;
+        // FP arithmetic and sqrt can create a NaN from non-NaN operands; fdemote is rewritten too.
+        for op in &[Fadd, Fsub, Fmul, Fdiv, Fma, Sqrt, Fdemote] {
+            assert!(produces_nan(*op), "{} should be canonicalized", op);
+        }
+        // NaN-propagating, conversion and bit-preserving opcodes must not be canonicalized.
+        for op in &[Fmin, Fmax, Ceil, Floor, Trunc, Nearest, Fpromote, FcvtFromSint,
+                    FcvtFromUint, Iadd, Copy]
+        {
+            assert!(!produces_nan(*op), "{} should not be canonicalized", op);
+        }
+    }
+
+    #[test]
+    fn illegal_set_reports_each_instruction() {
+        // The partial-mode report names every unconverted instruction with its opcode and EBB.
+        let errors = LegalizationErrors {
+            illegal: vec![
+                IllegalInst {
+                    inst: ir::Inst::new(3),
+                    opcode: ir::Opcode::F32const,
+                    ebb: ir::Ebb::new(1),
+                },
+                IllegalInst {
+                    inst: ir::Inst::new(7),
+                    opcode: ir::Opcode::Iadd,
+                    ebb: ir::Ebb::new(2),
+                },
+            ],
+        };
+        let text = errors.to_string();
+        assert!(text.contains("2 instruction(s) could not be legalized"));
+        assert!(text.contains("inst3 in ebb1: f32const"));
+        assert!(text.contains("inst7 in ebb2: iadd"));
+    }
+}